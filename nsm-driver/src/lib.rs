@@ -13,26 +13,29 @@
 //! then decoded from CBOR.
 
 #![cfg_attr(feature = "rustc-dep-of-std", no_std)]
+#[cfg(feature = "simulated")]
+mod simulated;
+
 use libc::ioctl;
 #[cfg(feature = "log")]
 use log::{debug, error};
-#[cfg(feature = "nix")]
-use {
-    nix::errno,
-    nix::request_code_readwrite,
-    nix::unistd::close,
-};
 use nsm_io::{ErrorCode, Request, Response};
+#[cfg(feature = "nix")]
+use {nix::errno, nix::request_code_readwrite, nix::unistd::close};
 
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+#[cfg(feature = "rand")]
+use rand_core::{CryptoRng, Error as RandError, RngCore};
 #[cfg(feature = "std")]
 use {
     std::fs::OpenOptions,
     std::mem,
     std::os::unix::io::{IntoRawFd, RawFd},
+    std::time::Duration,
     std::vec::Vec,
 };
-#[cfg(not(feature = "std"))]
-use alloc::vec::Vec;
 
 pub const DEV_FILE: &str = "/dev/nsm";
 pub const NSM_IOCTL_MAGIC: u8 = 0x0A;
@@ -41,9 +44,12 @@ pub const NSM_RESPONSE_MAX_SIZE: usize = 0x3000;
 
 pub trait Platform {
     fn open_dev() -> i32;
-    fn nsm_ioctl(fd: i32, message: &mut NsmMessage) -> Option<i32>;
+    /// Send `request` to the device and fill `response` with whatever the
+    /// device returned.
+    /// *Returns*: `Ok(len)` with the number of valid bytes written to
+    /// `response`, or `Err(errno)` with the raw `errno` value on failure.
+    fn nsm_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32>;
     fn close_dev(fd: i32);
-
 }
 
 #[cfg(feature = "nix")]
@@ -55,8 +61,36 @@ impl Platform for Nix {
         nsm_init()
     }
 
-    fn nsm_ioctl(fd: i32, message: &mut NsmMessage) -> Option<i32> {
-        nsm_ioctl(fd, message)
+    fn nsm_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32> {
+        nsm_ioctl(fd, request, response)
+    }
+
+    fn close_dev(fd: i32) {
+        nsm_exit(fd)
+    }
+}
+
+/// `Platform` implementation for the mainline Linux `/dev/nsm` driver (the
+/// one that shipped in `drivers/misc/nsm.c`), which uses a fixed,
+/// length-prefixed `#[repr(C)]` layout instead of the pair of raw slices
+/// the original Nitro Enclaves driver expects. The `nix_upstream` feature
+/// implies `nix`/`std` (it reuses `nsm_init`/`nsm_exit` and the `nix` crate's
+/// `errno`/`request_code_readwrite!`), so enabling it is all that's needed.
+/// Note that `nix` and `nix_upstream` are additive: if both are enabled,
+/// both `Nix` and `NixUpstream` are available and nothing stops a caller
+/// from picking the one that doesn't match the kernel driver actually
+/// present. Callers must select the layout that matches their kernel.
+#[cfg(feature = "nix_upstream")]
+pub struct NixUpstream;
+
+#[cfg(feature = "nix_upstream")]
+impl Platform for NixUpstream {
+    fn open_dev() -> i32 {
+        nsm_init()
+    }
+
+    fn nsm_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32> {
+        nsm_upstream_ioctl(fd, request, response)
     }
 
     fn close_dev(fd: i32) {
@@ -64,8 +98,30 @@ impl Platform for Nix {
     }
 }
 
+/// `Platform` implementation that answers requests in-process, without an
+/// `ioctl()` or a real `/dev/nsm` device, so that application code can be
+/// unit-tested and run in CI. See the `simulated` module for the
+/// in-memory device state this drives.
+#[cfg(feature = "simulated")]
+pub struct Simulated;
+
+#[cfg(feature = "simulated")]
+impl Platform for Simulated {
+    fn open_dev() -> i32 {
+        simulated::open_dev()
+    }
+
+    fn nsm_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32> {
+        simulated::nsm_ioctl(fd, request, response)
+    }
+
+    fn close_dev(fd: i32) {
+        simulated::close_dev(fd)
+    }
+}
 
-/// NSM message structure to be used with `ioctl()`.
+/// NSM message structure to be used with `ioctl()` on the Nitro Enclaves
+/// driver.
 #[repr(C)]
 pub struct NsmMessage<'a> {
     /// User-provided data for the request
@@ -74,6 +130,38 @@ pub struct NsmMessage<'a> {
     pub response: &'a mut [u8],
 }
 
+/// A fixed-capacity, length-prefixed request buffer, matching
+/// `struct nsm_data_req` from the mainline `drivers/misc/nsm.c` driver.
+#[cfg(feature = "nix_upstream")]
+#[repr(C)]
+pub struct NsmDataReq {
+    /// Number of valid bytes at the front of `data`.
+    pub len: u32,
+    /// CBOR-encoded request.
+    pub data: [u8; NSM_REQUEST_MAX_SIZE],
+}
+
+/// A fixed-capacity, length-prefixed response buffer, matching
+/// `struct nsm_data_resp` from the mainline `drivers/misc/nsm.c` driver.
+#[cfg(feature = "nix_upstream")]
+#[repr(C)]
+pub struct NsmDataResp {
+    /// Number of valid bytes at the front of `data`, filled in by the
+    /// driver on return.
+    pub len: u32,
+    /// CBOR-encoded response.
+    pub data: [u8; NSM_RESPONSE_MAX_SIZE],
+}
+
+/// NSM message structure to be used with `ioctl()` on the mainline Linux
+/// `/dev/nsm` driver, matching `struct nsm_msg`.
+#[cfg(feature = "nix_upstream")]
+#[repr(C)]
+pub struct NsmMsg {
+    pub req: NsmDataReq,
+    pub resp: NsmDataResp,
+}
+
 /// Encode an NSM `Request` value into a vector.  
 /// *Argument 1 (input)*: The NSM request.  
 /// *Returns*: The vector containing the CBOR encoding.
@@ -91,34 +179,79 @@ fn nsm_decode_response_from_cbor(response_data: &[u8]) -> Response {
     }
 }
 
-/// Do an `ioctl()` of a given type for a given message.  
-/// *Argument 1 (input)*: The descriptor to the device file.  
-/// *Argument 2 (input/output)*: The message to be sent and updated via `ioctl()`.  
-/// *Returns*: The status of the operation.
+/// Do an `ioctl()` of a given type for a given request/response pair, using
+/// the Nitro Enclaves driver's raw-slice message layout.
+/// *Argument 1 (input)*: The descriptor to the device file.
+/// *Argument 2 (input)*: The CBOR-encoded request.
+/// *Argument 3 (output)*: The buffer to be filled in by `ioctl()`.
+/// *Returns*: `Ok(len)` with the number of valid response bytes on success,
+/// or `Err(errno)` on failure.
 #[cfg(feature = "nix")]
-fn nsm_ioctl(fd: i32, message: &mut NsmMessage) -> Option<i32> {
+fn nsm_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32> {
+    let mut message = NsmMessage { request, response };
+
     let status = unsafe {
         ioctl(
             fd,
             request_code_readwrite!(NSM_IOCTL_MAGIC, 0, mem::size_of::<NsmMessage>()),
-            message,
+            &mut message,
         )
     };
 
     match status {
-        // If ioctl() succeeded, the status is the message's response code
-        0 => None,
+        // If ioctl() succeeded, the response buffer holds the message's response code
+        0 => Ok(message.response.len()),
 
         // If ioctl() failed, the error is given by errno
-        _ => Some(errno::errno()),
+        _ => Err(errno::errno()),
+    }
+}
+
+/// Do an `ioctl()` of a given type for a given request/response pair, using
+/// the mainline `/dev/nsm` driver's length-prefixed `nsm_msg` layout.
+/// *Argument 1 (input)*: The descriptor to the device file.
+/// *Argument 2 (input)*: The CBOR-encoded request.
+/// *Argument 3 (output)*: The buffer to be filled in with the CBOR-encoded
+/// response.
+/// *Returns*: `Ok(len)` with the number of valid response bytes on success,
+/// or `Err(errno)` on failure.
+#[cfg(feature = "nix_upstream")]
+fn nsm_upstream_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32> {
+    let mut msg = NsmMsg {
+        req: NsmDataReq {
+            len: request.len() as u32,
+            data: [0; NSM_REQUEST_MAX_SIZE],
+        },
+        resp: NsmDataResp {
+            len: 0,
+            data: [0; NSM_RESPONSE_MAX_SIZE],
+        },
+    };
+    msg.req.data[..request.len()].copy_from_slice(request);
+
+    let status = unsafe {
+        ioctl(
+            fd,
+            request_code_readwrite!(NSM_IOCTL_MAGIC, 0, mem::size_of::<NsmMsg>()),
+            &mut msg,
+        )
+    };
+
+    match status {
+        0 => {
+            let len = msg.resp.len as usize;
+            response[..len].copy_from_slice(&msg.resp.data[..len]);
+            Ok(len)
+        }
+        _ => Err(errno::errno()),
     }
 }
 
 /// Create a message with input data and output capacity from a given
 /// request, then send it to the NSM driver via `ioctl()` and wait
-/// for the driver's response.  
-/// *Argument 1 (input)*: The descriptor to the NSM device file.  
-/// *Argument 2 (input)*: The NSM request.  
+/// for the driver's response.
+/// *Argument 1 (input)*: The descriptor to the NSM device file.
+/// *Argument 2 (input)*: The NSM request.
 /// *Returns*: The corresponding NSM response from the driver.
 pub fn nsm_process_request<P: Platform>(fd: i32, request: Request) -> Response {
     let cbor_request = nsm_encode_request_to_cbor(request);
@@ -129,15 +262,11 @@ pub fn nsm_process_request<P: Platform>(fd: i32, request: Request) -> Response {
     }
 
     let mut cbor_response: [u8; NSM_RESPONSE_MAX_SIZE] = [0; NSM_RESPONSE_MAX_SIZE];
-    let mut message = NsmMessage {
-        request: &cbor_request,
-        response: &mut cbor_response,
-    };
-    let status = P::nsm_ioctl(fd, &mut message);
+    let status = P::nsm_ioctl(fd, &cbor_request, &mut cbor_response);
 
     match status {
-        None => nsm_decode_response_from_cbor(&message.response),
-        Some(errno) => {
+        Ok(len) => nsm_decode_response_from_cbor(&cbor_response[..len]),
+        Err(errno) => {
             if errno == 90 {
                 Response::Error(ErrorCode::InputTooLarge)
             } else {
@@ -147,7 +276,134 @@ pub fn nsm_process_request<P: Platform>(fd: i32, request: Request) -> Response {
     }
 }
 
-/// NSM library initialization function.  
+/// A low-level failure of an NSM `ioctl()`, as opposed to the
+/// application-level `ErrorCode`s decoded from a successful response.
+/// Unlike `nsm_process_request`, which collapses every non-`EMSGSIZE`
+/// failure into `ErrorCode::InternalError`, this threads the raw condition
+/// through so callers can tell a timeout apart from, say, `EINTR` or the
+/// device having gone away.
+#[cfg(feature = "nix")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsmIoctlError {
+    /// The request did not complete before the caller-supplied deadline.
+    /// Mirrors the driver's own `NSM_DEFAULT_TIMEOUT_MSECS` virtqueue
+    /// timeout, but lets the caller pick a tighter (or looser) bound.
+    Timeout,
+    /// The `ioctl()` itself failed; this is the raw `errno` value.
+    Errno(i32),
+}
+
+/// Signal handler for the deadline alarm. It only needs to exist (rather
+/// than terminate the process, `SIGALRM`'s default disposition) so that
+/// delivery interrupts the blocking `ioctl()` with `EINTR`; it does nothing
+/// else.
+#[cfg(feature = "nix")]
+extern "C" fn nsm_deadline_handler(_signum: libc::c_int) {}
+
+/// Arm a one-shot real-time timer that delivers `SIGALRM` to the process
+/// after `timeout` elapses, interrupting the blocking syscall of whichever
+/// thread takes the signal; in a process with several threads that is not
+/// necessarily the calling thread.
+/// `/dev/nsm` has no `poll()` support (the driver doesn't implement a
+/// `.poll` file op, so a plain `poll()` on the fd returns ready
+/// immediately without actually waiting), so the deadline has to be
+/// enforced by interrupting the `ioctl()` itself rather than by waiting
+/// on the descriptor first.
+/// *Argument 1 (input)*: The maximum time to wait before interrupting.
+/// *Returns*: `Err` with the raw `errno` if arming the timer failed.
+#[cfg(feature = "nix")]
+fn nsm_arm_deadline(timeout: Duration) -> Result<(), NsmIoctlError> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = nsm_deadline_handler as usize;
+        if libc::sigaction(libc::SIGALRM, &action, core::ptr::null_mut()) != 0 {
+            return Err(NsmIoctlError::Errno(errno::errno()));
+        }
+    }
+
+    let timer = libc::itimerval {
+        it_interval: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        it_value: libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: libc::suseconds_t::from(timeout.subsec_micros()),
+        },
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, core::ptr::null_mut()) } != 0 {
+        return Err(NsmIoctlError::Errno(errno::errno()));
+    }
+    Ok(())
+}
+
+/// Disarm the deadline timer armed by `nsm_arm_deadline`, e.g. once the
+/// `ioctl()` it was guarding has returned.
+#[cfg(feature = "nix")]
+fn nsm_disarm_deadline() {
+    let disarmed = libc::itimerval {
+        it_interval: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        it_value: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+    };
+    unsafe {
+        libc::setitimer(libc::ITIMER_REAL, &disarmed, core::ptr::null_mut());
+    }
+}
+
+/// Like `nsm_process_request`, but fails with `NsmIoctlError::Timeout`
+/// instead of blocking forever when the NSM device doesn't respond within
+/// `timeout` — enforced by arming a `SIGALRM` deadline around the
+/// `ioctl()` so it gets interrupted rather than relying on `poll()`, which
+/// `/dev/nsm` doesn't support — and surfaces the raw `errno` of any other
+/// ioctl failure instead of collapsing it into `ErrorCode::InternalError`.
+/// *Argument 1 (input)*: The descriptor to the NSM device file.
+/// *Argument 2 (input)*: The NSM request.
+/// *Argument 3 (input)*: The maximum time to wait for a response.
+/// *Returns*: The corresponding NSM response from the driver, or the
+/// low-level error that prevented one from arriving.
+#[cfg(feature = "nix")]
+pub fn nsm_process_request_timeout<P: Platform>(
+    fd: i32,
+    request: Request,
+    timeout: Duration,
+) -> Result<Response, NsmIoctlError> {
+    let cbor_request = nsm_encode_request_to_cbor(request);
+
+    // Check if the request is too large
+    if cbor_request.len() > NSM_REQUEST_MAX_SIZE {
+        return Ok(Response::Error(ErrorCode::InputTooLarge));
+    }
+
+    nsm_arm_deadline(timeout)?;
+    let mut cbor_response: [u8; NSM_RESPONSE_MAX_SIZE] = [0; NSM_RESPONSE_MAX_SIZE];
+    let status = P::nsm_ioctl(fd, &cbor_request, &mut cbor_response);
+    // Once the one-shot timer has fired, its remaining time reads as zero.
+    let mut remaining: libc::itimerval = unsafe { mem::zeroed() };
+    let deadline_expired = unsafe { libc::getitimer(libc::ITIMER_REAL, &mut remaining) } == 0
+        && remaining.it_value.tv_sec == 0
+        && remaining.it_value.tv_usec == 0;
+    nsm_disarm_deadline();
+
+    match status {
+        Ok(len) => Ok(nsm_decode_response_from_cbor(&cbor_response[..len])),
+        // EMSGSIZE
+        Err(90) => Ok(Response::Error(ErrorCode::InputTooLarge)),
+        // Our deadline alarm interrupted the ioctl, or the driver's own
+        // NSM_DEFAULT_TIMEOUT_MSECS virtqueue timeout expired first. An
+        // EINTR caused by any other signal is reported as `Errno(EINTR)`.
+        Err(libc::EINTR) if deadline_expired => Err(NsmIoctlError::Timeout),
+        Err(libc::ETIMEDOUT) => Err(NsmIoctlError::Timeout),
+        Err(errno) => Err(NsmIoctlError::Errno(errno)),
+    }
+}
+
+/// NSM library initialization function.
 /// *Returns*: A descriptor for the opened device file.
 #[cfg(feature = "nix")]
 pub fn nsm_init() -> i32 {
@@ -166,7 +422,7 @@ pub fn nsm_init() -> i32 {
     }
 }
 
-/// NSM library exit function.  
+/// NSM library exit function.
 /// *Argument 1 (input)*: The descriptor for the opened device file, as
 /// obtained from `nsm_init()`.
 #[cfg(feature = "nix")]
@@ -177,3 +433,186 @@ pub fn nsm_exit(fd: i32) {
         Err(e) => error!("File of descriptor {} failed to close: {}", fd, e),
     }
 }
+
+/// An RAII handle to an open NSM device. This is the recommended API for
+/// services that issue many requests: it closes the underlying descriptor
+/// automatically on `Drop` (instead of requiring the caller to remember
+/// `nsm_exit`), and it reuses owned request/response scratch buffers across
+/// calls instead of allocating a fresh `Vec` and `NSM_RESPONSE_MAX_SIZE`
+/// array per `process()`. The free `nsm_process_request` functions remain
+/// available for callers that already manage the descriptor themselves.
+///
+/// Reusing the request buffer across calls needs `std::io::Write`, so this
+/// type is only available with the `std` feature.
+#[cfg(feature = "std")]
+pub struct NsmDevice<P: Platform> {
+    fd: i32,
+    request: Vec<u8>,
+    response: [u8; NSM_RESPONSE_MAX_SIZE],
+    _platform: PhantomData<P>,
+}
+
+#[cfg(feature = "std")]
+impl<P: Platform> NsmDevice<P> {
+    /// Open a new NSM device handle.
+    pub fn new() -> Self {
+        NsmDevice {
+            fd: P::open_dev(),
+            request: Vec::with_capacity(NSM_REQUEST_MAX_SIZE),
+            response: [0; NSM_RESPONSE_MAX_SIZE],
+            _platform: PhantomData,
+        }
+    }
+
+    /// The underlying device descriptor, e.g. for use with
+    /// `nsm_process_request_timeout`.
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Send `request` to the device and wait for its response, reusing
+    /// this handle's scratch buffers instead of allocating new ones.
+    /// *Argument 1 (input)*: The NSM request.
+    /// *Returns*: The corresponding NSM response from the driver.
+    pub fn process(&mut self, request: Request) -> Response {
+        self.request.clear();
+        if serde_cbor::to_writer(&mut self.request, &request).is_err() {
+            return Response::Error(ErrorCode::InternalError);
+        }
+
+        // Check if the request is too large
+        if self.request.len() > NSM_REQUEST_MAX_SIZE {
+            return Response::Error(ErrorCode::InputTooLarge);
+        }
+
+        match P::nsm_ioctl(self.fd, &self.request, &mut self.response) {
+            Ok(len) => nsm_decode_response_from_cbor(&self.response[..len]),
+            Err(errno) => {
+                if errno == 90 {
+                    Response::Error(ErrorCode::InputTooLarge)
+                } else {
+                    Response::Error(ErrorCode::InternalError)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: Platform> Default for NsmDevice<P> {
+    fn default() -> Self {
+        NsmDevice::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: Platform> Drop for NsmDevice<P> {
+    fn drop(&mut self) {
+        P::close_dev(self.fd);
+    }
+}
+
+/// An entropy source backed by the NSM `GetRandom` command, implementing
+/// `rand_core::RngCore` and `CryptoRng` so it can be used to seed other
+/// CSPRNGs or draw keys directly from the secure module.
+/// Internally buffers one `GetRandom` response at a time so that
+/// `next_u32`/`next_u64`/`fill_bytes` don't issue an `ioctl()` per call.
+#[cfg(feature = "rand")]
+pub struct NsmRng<P: Platform> {
+    fd: i32,
+    buffer: Vec<u8>,
+    pos: usize,
+    _platform: PhantomData<P>,
+}
+
+#[cfg(feature = "rand")]
+impl<P: Platform> NsmRng<P> {
+    /// Create a new `NsmRng` that reads entropy through the given,
+    /// already-open NSM device descriptor.
+    /// *Argument 1 (input)*: The descriptor to the NSM device file.
+    pub fn new(fd: i32) -> Self {
+        NsmRng {
+            fd,
+            buffer: Vec::new(),
+            pos: 0,
+            _platform: PhantomData,
+        }
+    }
+
+    /// Issue a `GetRandom` request and replace the refill buffer with the
+    /// random bytes it returned.
+    fn refill(&mut self) -> Result<(), ErrorCode> {
+        match nsm_process_request::<P>(self.fd, Request::GetRandom) {
+            Response::GetRandom { random } => {
+                self.buffer = random;
+                self.pos = 0;
+                Ok(())
+            }
+            Response::Error(err) => Err(err),
+            _ => Err(ErrorCode::InternalError),
+        }
+    }
+
+    /// Serve a single byte of entropy from the refill buffer, requesting
+    /// more from the device once it has been drained.
+    fn next_byte(&mut self) -> Result<u8, ErrorCode> {
+        if self.pos >= self.buffer.len() {
+            self.refill()?;
+            if self.buffer.is_empty() {
+                return Err(ErrorCode::InternalError);
+            }
+        }
+
+        let byte = self.buffer[self.pos];
+        self.pos += 1;
+        Ok(byte)
+    }
+}
+
+#[cfg(feature = "rand")]
+impl<P: Platform> RngCore for NsmRng<P> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest)
+            .expect("NSM device failed to provide entropy");
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), RandError> {
+        for byte in dest.iter_mut() {
+            *byte = self
+                .next_byte()
+                .map_err(|err| RandError::new(NsmRngError(err)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand")]
+impl<P: Platform> CryptoRng for NsmRng<P> {}
+
+/// The error produced when `NsmRng` cannot obtain entropy from the NSM
+/// device, wrapping the `ErrorCode` reported by the device.
+#[cfg(feature = "rand")]
+#[derive(Debug)]
+pub struct NsmRngError(pub ErrorCode);
+
+#[cfg(feature = "rand")]
+impl core::fmt::Display for NsmRngError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "NSM device failed to provide entropy: {:?}", self.0)
+    }
+}
+
+#[cfg(all(feature = "rand", feature = "std"))]
+impl std::error::Error for NsmRngError {}
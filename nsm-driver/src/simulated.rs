@@ -0,0 +1,309 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! In-process device state backing the [`Simulated`](crate::Simulated)
+//! `Platform`. Nothing here talks to `/dev/nsm` or issues an `ioctl()`: a
+//! simulated device is just an entry in a table, keyed by the descriptor
+//! handed back from `open_dev()`, holding 32 PCR banks and a seedable PRNG.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+use nsm_io::{Digest, ErrorCode, Request, Response};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest as _, Sha384};
+
+/// Number of PCR banks a simulated device exposes, matching the real NSM.
+const PCR_COUNT: usize = 32;
+
+/// One simulated NSM device: its PCR banks, which of them are locked, and
+/// the PRNG backing `GetRandom`.
+struct SimulatedDevice {
+    pcrs: [[u8; 48]; PCR_COUNT],
+    locked: [bool; PCR_COUNT],
+    rng: SplitMix64,
+}
+
+impl SimulatedDevice {
+    fn new(seed: u64) -> Self {
+        SimulatedDevice {
+            pcrs: [[0u8; 48]; PCR_COUNT],
+            locked: [false; PCR_COUNT],
+            rng: SplitMix64::new(seed),
+        }
+    }
+}
+
+/// A small, deterministic, seedable PRNG. Good enough to make `GetRandom`
+/// behave like an entropy source in tests; not suitable for anything that
+/// needs real randomness, which is the whole point of keeping it out of
+/// the `Nix`/`NixUpstream` code paths.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+static NEXT_FD: AtomicI32 = AtomicI32::new(1);
+static DEVICES: Mutex<Option<HashMap<i32, SimulatedDevice>>> = Mutex::new(None);
+
+/// Allocate a new simulated device and return the descriptor it is keyed
+/// under.
+pub(crate) fn open_dev() -> i32 {
+    let fd = NEXT_FD.fetch_add(1, Ordering::Relaxed);
+    let mut devices = DEVICES.lock().unwrap();
+    devices
+        .get_or_insert_with(HashMap::new)
+        .insert(fd, SimulatedDevice::new(fd as u64));
+    fd
+}
+
+/// Forget the simulated device behind `fd`.
+pub(crate) fn close_dev(fd: i32) {
+    if let Some(devices) = DEVICES.lock().unwrap().as_mut() {
+        devices.remove(&fd);
+    }
+}
+
+/// Decode `request` as CBOR, process it against the simulated device
+/// behind `fd`, and CBOR-encode the result into `response`.
+pub(crate) fn nsm_ioctl(fd: i32, request: &[u8], response: &mut [u8]) -> Result<usize, i32> {
+    let request: Request = match serde_cbor::from_slice(request) {
+        Ok(request) => request,
+        Err(_) => return Err(libc::EINVAL),
+    };
+
+    let mut guard = DEVICES.lock().unwrap();
+    let device = guard
+        .get_or_insert_with(HashMap::new)
+        .entry(fd)
+        .or_insert_with(|| SimulatedDevice::new(fd as u64));
+
+    let encoded = serde_cbor::to_vec(&process(device, request)).unwrap();
+    if encoded.len() > response.len() {
+        return Err(libc::EMSGSIZE);
+    }
+    response[..encoded.len()].copy_from_slice(&encoded);
+    Ok(encoded.len())
+}
+
+/// Apply a single `Request` to `device` and produce the `Response` a real
+/// NSM device would give for it.
+fn process(device: &mut SimulatedDevice, request: Request) -> Response {
+    match request {
+        Request::DescribeNSM => Response::DescribeNSM {
+            version_major: 1,
+            version_minor: 0,
+            version_patch: 0,
+            module_id: "sim-nsm0".to_string(),
+            max_pcrs: PCR_COUNT as u16,
+            locked_pcrs: device
+                .locked
+                .iter()
+                .enumerate()
+                .filter(|(_, &locked)| locked)
+                .map(|(index, _)| index as u16)
+                .collect::<BTreeSet<u16>>(),
+            digest: Digest::SHA384,
+        },
+        Request::GetRandom => {
+            let mut random = vec![0u8; 256];
+            device.rng.fill(&mut random);
+            Response::GetRandom { random }
+        }
+        // Read the lock flag before taking the mutable PCR-bank borrow
+        // below, instead of re-reading `device.locked` while it's borrowed.
+        Request::ExtendPCR { index, data } => match device.locked.get(index as usize).copied() {
+            None => Response::Error(ErrorCode::InvalidIndex),
+            Some(true) => Response::Error(ErrorCode::ReadOnlyIndex),
+            Some(false) => {
+                let pcr = pcr_bank(device, index).expect("index already bounds-checked above");
+                let digest = Sha384::new()
+                    .chain_update(&pcr[..])
+                    .chain_update(&data)
+                    .finalize();
+                pcr.copy_from_slice(&digest);
+                Response::ExtendPCR { data: pcr.to_vec() }
+            }
+        },
+        Request::DescribePCR { index } => match device.locked.get(index as usize).copied() {
+            None => Response::Error(ErrorCode::InvalidIndex),
+            Some(lock) => {
+                let pcr = pcr_bank(device, index).expect("index already bounds-checked above");
+                Response::DescribePCR {
+                    lock,
+                    data: pcr.to_vec(),
+                }
+            }
+        },
+        Request::LockPCR { index } => match device.locked.get_mut(index as usize) {
+            None => Response::Error(ErrorCode::InvalidIndex),
+            Some(locked) => {
+                *locked = true;
+                Response::LockPCR
+            }
+        },
+        Request::LockPCRs { range } => {
+            for locked in device.locked.iter_mut().take(range as usize) {
+                *locked = true;
+            }
+            Response::LockPCRs
+        }
+        Request::Attestation {
+            public_key,
+            user_data,
+            nonce,
+        } => Response::Attestation {
+            document: attestation_document(device, public_key, user_data, nonce),
+        },
+    }
+}
+
+fn pcr_bank(device: &mut SimulatedDevice, index: u16) -> Option<&mut [u8; 48]> {
+    device.pcrs.get_mut(index as usize)
+}
+
+/// A deterministic, COSE-like stand-in for a real attestation document:
+/// CBOR-encodes the current PCR map alongside the caller-supplied
+/// `user_data`/`nonce`/`public_key`, without any of the signing a real
+/// NSM device would add.
+#[derive(Serialize)]
+struct SimulatedAttestationDocument {
+    module_id: String,
+    pcrs: BTreeMap<u16, ByteBuf>,
+    user_data: Option<ByteBuf>,
+    nonce: Option<ByteBuf>,
+    public_key: Option<ByteBuf>,
+}
+
+fn attestation_document(
+    device: &SimulatedDevice,
+    public_key: Option<ByteBuf>,
+    user_data: Option<ByteBuf>,
+    nonce: Option<ByteBuf>,
+) -> Vec<u8> {
+    let pcrs = device
+        .pcrs
+        .iter()
+        .enumerate()
+        .map(|(index, pcr)| (index as u16, ByteBuf::from(pcr.to_vec())))
+        .collect();
+
+    let document = SimulatedAttestationDocument {
+        module_id: "sim-nsm0".to_string(),
+        pcrs,
+        user_data,
+        nonce,
+        public_key,
+    };
+    serde_cbor::to_vec(&document).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nsm_process_request, Platform, Simulated};
+
+    #[test]
+    fn extend_pcr_follows_sha384_extend_rule() {
+        let fd = Simulated::open_dev();
+        let response = nsm_process_request::<Simulated>(
+            fd,
+            Request::ExtendPCR {
+                index: 0,
+                data: vec![1, 2, 3],
+            },
+        );
+        let expected = Sha384::new()
+            .chain_update([0u8; 48])
+            .chain_update([1, 2, 3])
+            .finalize()
+            .to_vec();
+        match response {
+            Response::ExtendPCR { data } => assert_eq!(data, expected),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        match nsm_process_request::<Simulated>(fd, Request::DescribePCR { index: 0 }) {
+            Response::DescribePCR { lock, data } => {
+                assert!(!lock);
+                assert_eq!(data, expected);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locked_pcr_rejects_further_extends() {
+        let fd = Simulated::open_dev();
+        match nsm_process_request::<Simulated>(fd, Request::LockPCR { index: 1 }) {
+            Response::LockPCR => {}
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        match nsm_process_request::<Simulated>(
+            fd,
+            Request::ExtendPCR {
+                index: 1,
+                data: vec![9],
+            },
+        ) {
+            Response::Error(ErrorCode::ReadOnlyIndex) => {}
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn out_of_range_index_is_invalid() {
+        let fd = Simulated::open_dev();
+        match nsm_process_request::<Simulated>(fd, Request::DescribePCR { index: 32 }) {
+            Response::Error(ErrorCode::InvalidIndex) => {}
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn get_random_returns_a_full_buffer_and_does_not_repeat() {
+        let fd = Simulated::open_dev();
+        let first = match nsm_process_request::<Simulated>(fd, Request::GetRandom) {
+            Response::GetRandom { random } => random,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let second = match nsm_process_request::<Simulated>(fd, Request::GetRandom) {
+            Response::GetRandom { random } => random,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        assert_eq!(first.len(), 256);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn split_mix64_is_deterministic_for_a_given_seed() {
+        let mut a = SplitMix64::new(42);
+        let mut b = SplitMix64::new(42);
+        let (mut buf_a, mut buf_b) = ([0u8; 64], [0u8; 64]);
+        a.fill(&mut buf_a);
+        b.fill(&mut buf_b);
+        assert_eq!(buf_a, buf_b);
+    }
+}